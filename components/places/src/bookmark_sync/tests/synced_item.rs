@@ -9,7 +9,7 @@ use crate::{
     storage::RowId,
     types::{SyncGuid, Timestamp},
 };
-use rusqlite::Row;
+use rusqlite::{types::ToSql, Row};
 
 use sql_support::{self, ConnExt};
 use sync15::ServerTimestamp;
@@ -47,6 +47,17 @@ where
     }
 }
 
+/// The content key used to match a remotely-created item with a local item
+/// that has different GUID but the same "content", so that the merger can
+/// treat them as the same logical node. Tombstones, separators and livemarks
+/// have no content key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SyncedBookmarkContent {
+    Bookmark { title: String, url: String },
+    Folder { title: String, position: i64 },
+    Query { title: String },
+}
+
 #[derive(Debug, Default, PartialEq)]
 pub struct SyncedBookmarkItem {
     pub id: SyncedBookmarkValue<RowId>,
@@ -152,6 +163,379 @@ impl SyncedBookmarkItem {
         self
     }
 
+    // Stage this item as a row in moz_bookmarks_synced, as though it had
+    // just arrived from the server. Every column is written explicitly;
+    // `Unspecified` fields are written as NULL, except `needs_merge` (true),
+    // `server_modified` (0), `validity` (Valid), `deleted` (false), `kind`
+    // (-1) and `date_added` (now). `url`, if specified, is interned into moz_places to
+    // obtain a placeId, and `tags` are staged into
+    // moz_bookmarks_synced_tag_relation/moz_tags.
+    pub fn insert(&self, conn: &PlacesDb) -> Result<()> {
+        let guid = match &self.guid {
+            SyncedBookmarkValue::Specified(guid) => guid.clone(),
+            SyncedBookmarkValue::Unspecified => {
+                return Err("SyncedBookmarkItem::insert requires a guid".into());
+            }
+        };
+        let place_id = match &self.url {
+            SyncedBookmarkValue::Specified(Some(url)) => Some(Self::ensure_place_id(conn, url)?),
+            _ => None,
+        };
+        // The column is a non-null integer, with -1 as the "no kind"
+        // sentinel - same convention as `insert_tombstone` uses.
+        let kind: i8 = match Self::unwrap_or_default(&self.kind, None) {
+            Some(kind) => kind as u8 as i8,
+            None => -1,
+        };
+        let validity = Self::unwrap_or_default(&self.validity, SyncedBookmarkValidity::Valid) as u8;
+
+        conn.execute_named_cache(
+            "INSERT INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge,
+                                               validity, isDeleted, kind, dateAdded, title,
+                                               placeId, keyword, description, loadInSidebar,
+                                               smartBookmarkName, feedUrl, siteUrl)
+             VALUES (:guid, :parent_guid, :server_modified, :needs_merge,
+                     :validity, :deleted, :kind, :date_added, :title,
+                     :place_id, :keyword, :description, :load_in_sidebar,
+                     :smart_bookmark_name, :feed_url, :site_url)",
+            &[
+                (":guid", &guid as &dyn ToSql),
+                (
+                    ":parent_guid",
+                    &Self::unwrap_or_default(&self.parent_guid, None) as &dyn ToSql,
+                ),
+                (
+                    ":server_modified",
+                    &Self::unwrap_or_default(&self.server_modified, ServerTimestamp(0.0)).0
+                        as &dyn ToSql,
+                ),
+                (
+                    ":needs_merge",
+                    &Self::unwrap_or_default(&self.needs_merge, true) as &dyn ToSql,
+                ),
+                (":validity", &validity as &dyn ToSql),
+                (
+                    ":deleted",
+                    &Self::unwrap_or_default(&self.deleted, false) as &dyn ToSql,
+                ),
+                (":kind", &kind as &dyn ToSql),
+                (
+                    ":date_added",
+                    &Self::unwrap_or_default(&self.date_added, Timestamp::now()) as &dyn ToSql,
+                ),
+                (
+                    ":title",
+                    &Self::unwrap_or_default(&self.title, None) as &dyn ToSql,
+                ),
+                (":place_id", &place_id as &dyn ToSql),
+                (
+                    ":keyword",
+                    &Self::unwrap_or_default(&self.keyword, None) as &dyn ToSql,
+                ),
+                (
+                    ":description",
+                    &Self::unwrap_or_default(&self.description, None) as &dyn ToSql,
+                ),
+                (
+                    ":load_in_sidebar",
+                    &Self::unwrap_or_default(&self.load_in_sidebar, None) as &dyn ToSql,
+                ),
+                (
+                    ":smart_bookmark_name",
+                    &Self::unwrap_or_default(&self.smart_bookmark_name, None) as &dyn ToSql,
+                ),
+                (
+                    ":feed_url",
+                    &Self::unwrap_or_default(&self.feed_url, None) as &dyn ToSql,
+                ),
+                (
+                    ":site_url",
+                    &Self::unwrap_or_default(&self.site_url, None) as &dyn ToSql,
+                ),
+            ],
+        )?;
+
+        if let SyncedBookmarkValue::Specified(tags) = &self.tags {
+            let item_id = RowId(conn.conn().last_insert_rowid());
+            Self::stage_tags(conn, item_id, tags)?;
+        }
+        Ok(())
+    }
+
+    // Stage a tombstone - a deletion that arrived from the server for
+    // `guid`. Nothing else about the item is known, so every other column
+    // is left at its default.
+    pub fn insert_tombstone(conn: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+        conn.execute_named_cache(
+            "INSERT INTO moz_bookmarks_synced(guid, isDeleted, needsMerge, kind)
+             VALUES (:guid, 1, 1, -1)",
+            &[(":guid", guid)],
+        )?;
+        Ok(())
+    }
+
+    // The content key used to dedupe this item against a local item with a
+    // different GUID. `position` is the item's position within its parent
+    // in the mirror's structure, which folders use as part of their key
+    // since their title alone is too likely to collide. Returns `None` for
+    // tombstones, separators, livemarks, bookmarks without a url, and items
+    // without a kind.
+    pub fn content_key(&self, position: i64) -> Option<SyncedBookmarkContent> {
+        if let SyncedBookmarkValue::Specified(true) = self.deleted {
+            return None;
+        }
+        let kind = match &self.kind {
+            SyncedBookmarkValue::Specified(Some(kind)) => *kind,
+            _ => return None,
+        };
+        let title = Self::normalize_title(self.title_str());
+        match kind {
+            SyncedBookmarkKind::Bookmark => {
+                let url = match &self.url {
+                    SyncedBookmarkValue::Specified(Some(url)) => {
+                        let mut url = url.clone();
+                        url.set_fragment(None);
+                        url.to_string()
+                    }
+                    _ => return None,
+                };
+                Some(SyncedBookmarkContent::Bookmark { title, url })
+            }
+            SyncedBookmarkKind::Folder => Some(SyncedBookmarkContent::Folder { title, position }),
+            // Query URLs are volatile, so only the title is significant.
+            SyncedBookmarkKind::Query => Some(SyncedBookmarkContent::Query { title }),
+            SyncedBookmarkKind::Livemark | SyncedBookmarkKind::Separator => None,
+        }
+    }
+
+    // Find the GUID of a local, not-yet-synced Places item with the same
+    // content key as this item. Returns `None` if there's no such item, or
+    // if there's more than one - an ambiguous match must never auto-merge.
+    pub fn find_local_dedupe_candidate(
+        &self,
+        conn: &PlacesDb,
+        position: i64,
+    ) -> Result<Option<SyncGuid>> {
+        // `b.title` isn't normalized the way our content key's title is, so
+        // we can't push the title comparison into SQL - fetch candidates
+        // matching on the rest of the key and compare normalized titles
+        // here instead.
+        let candidates = match self.content_key(position) {
+            Some(SyncedBookmarkContent::Bookmark { title, url }) => {
+                // The key's url has its fragment stripped, so a local url
+                // matches if it's the same url, or that url plus a fragment.
+                let rows: Vec<(SyncGuid, Option<String>)> = conn.query_rows_and_then_named(
+                    "SELECT b.guid, b.title
+                       FROM moz_bookmarks b
+                       JOIN moz_places p ON p.id = b.fk
+                      WHERE b.type = 1
+                        AND (p.url = :url
+                             OR substr(p.url, 1, length(:url) + 1) = :url || '#')
+                        AND b.guid NOT IN (SELECT guid FROM moz_bookmarks_synced)",
+                    &[(":url", &url)],
+                    |row| Ok((row.get_checked(0)?, row.get_checked(1)?)),
+                )?;
+                Self::filter_by_normalized_title(rows, &title)
+            }
+            Some(SyncedBookmarkContent::Folder { title, position }) => {
+                // `position` is only unique within a parent, so scope the
+                // match to the same parent rather than comparing it alone.
+                let parent_guid = match &self.parent_guid {
+                    SyncedBookmarkValue::Specified(Some(parent_guid)) => parent_guid,
+                    _ => return Ok(None),
+                };
+                let rows: Vec<(SyncGuid, Option<String>)> = conn.query_rows_and_then_named(
+                    "SELECT b.guid, b.title
+                       FROM moz_bookmarks b
+                       JOIN moz_bookmarks pb ON pb.id = b.parent
+                      WHERE b.type = 2
+                        AND b.position = :position
+                        AND pb.guid = :parent_guid
+                        AND b.guid NOT IN (SELECT guid FROM moz_bookmarks_synced)",
+                    &[(":position", &position), (":parent_guid", parent_guid)],
+                    |row| Ok((row.get_checked(0)?, row.get_checked(1)?)),
+                )?;
+                Self::filter_by_normalized_title(rows, &title)
+            }
+            Some(SyncedBookmarkContent::Query { title }) => {
+                let rows: Vec<(SyncGuid, Option<String>)> = conn.query_rows_and_then_named(
+                    "SELECT b.guid, b.title
+                       FROM moz_bookmarks b
+                       JOIN moz_places p ON p.id = b.fk
+                      WHERE b.type = 1
+                        AND p.url LIKE 'place:%'
+                        AND b.guid NOT IN (SELECT guid FROM moz_bookmarks_synced)",
+                    &[],
+                    |row| Ok((row.get_checked(0)?, row.get_checked(1)?)),
+                )?;
+                Self::filter_by_normalized_title(rows, &title)
+            }
+            None => return Ok(None),
+        };
+        Ok(match candidates.len() {
+            1 => candidates.into_iter().next(),
+            _ => None,
+        })
+    }
+
+    // Keep only the candidates whose title, once normalized the same way
+    // `content_key` normalizes it, equals `title` - so a local bookmark
+    // titled e.g. " Foo  Bar" still matches a remote item normalized to
+    // "Foo Bar".
+    fn filter_by_normalized_title(
+        candidates: Vec<(SyncGuid, Option<String>)>,
+        title: &str,
+    ) -> Vec<SyncGuid> {
+        candidates
+            .into_iter()
+            .filter(|(_, candidate_title)| {
+                Self::normalize_title(candidate_title.as_ref().map(String::as_str)) == title
+            })
+            .map(|(guid, _)| guid)
+            .collect()
+    }
+
+    // Classify this item the way the merger does: a structurally broken
+    // record is `Replace` (take the other side's version instead), one
+    // that's usable locally but needs correcting before it's re-uploaded
+    // is `Reupload`, and everything else is `Valid`.
+    pub fn compute_validity(&self) -> SyncedBookmarkValidity {
+        let kind = match &self.kind {
+            SyncedBookmarkValue::Specified(Some(kind)) => *kind,
+            _ => return SyncedBookmarkValidity::Valid,
+        };
+        match kind {
+            SyncedBookmarkKind::Bookmark => match &self.url {
+                SyncedBookmarkValue::Specified(Some(_)) => SyncedBookmarkValidity::Valid,
+                _ => SyncedBookmarkValidity::Replace,
+            },
+            SyncedBookmarkKind::Query => match &self.url {
+                SyncedBookmarkValue::Specified(Some(url)) if url.scheme() == "place" => {
+                    SyncedBookmarkValidity::Valid
+                }
+                _ => SyncedBookmarkValidity::Reupload,
+            },
+            SyncedBookmarkKind::Livemark => {
+                // An empty string is just as unusable as a missing value.
+                let feed_missing = Self::unwrap_or_default(&self.feed_url, None)
+                    .map_or(true, |url| url.is_empty());
+                let site_missing = Self::unwrap_or_default(&self.site_url, None)
+                    .map_or(true, |url| url.is_empty());
+                if feed_missing && site_missing {
+                    SyncedBookmarkValidity::Reupload
+                } else {
+                    SyncedBookmarkValidity::Valid
+                }
+            }
+            SyncedBookmarkKind::Folder | SyncedBookmarkKind::Separator => {
+                SyncedBookmarkValidity::Valid
+            }
+        }
+    }
+
+    // Canonicalize the fields that make this item `Reupload`, then
+    // reclassify. Does nothing if the item isn't `Reupload`.
+    pub fn repair(&mut self) {
+        if self.compute_validity() != SyncedBookmarkValidity::Reupload {
+            return;
+        }
+        // A query whose url isn't a `place:` url can't be driven by the
+        // tree builder as-is; fall back to the canonical "match everything"
+        // query rather than leaving an unusable url in place.
+        if let SyncedBookmarkValue::Specified(Some(kind)) = &self.kind {
+            if *kind == SyncedBookmarkKind::Query {
+                let has_place_url = matches!(
+                    &self.url,
+                    SyncedBookmarkValue::Specified(Some(url)) if url.scheme() == "place"
+                );
+                if !has_place_url {
+                    self.url = SyncedBookmarkValue::Specified(Some(
+                        Url::parse("place:").expect("place: is a valid url"),
+                    ));
+                }
+            }
+        }
+        if let SyncedBookmarkValue::Specified(Some(feed_url)) = &self.feed_url {
+            if feed_url.is_empty() {
+                self.feed_url = SyncedBookmarkValue::Specified(None);
+            }
+        }
+        if let SyncedBookmarkValue::Specified(Some(site_url)) = &self.site_url {
+            if site_url.is_empty() {
+                self.site_url = SyncedBookmarkValue::Specified(None);
+            }
+        }
+        if let SyncedBookmarkValue::Specified(Some(keyword)) = &self.keyword {
+            let trimmed = keyword.trim();
+            self.keyword = SyncedBookmarkValue::Specified(if trimmed.is_empty() {
+                None
+            } else {
+                Some(trimmed.to_string())
+            });
+        }
+        self.validity = SyncedBookmarkValue::Specified(self.compute_validity());
+    }
+
+    fn title_str(&self) -> Option<&str> {
+        match &self.title {
+            SyncedBookmarkValue::Specified(title) => title.as_ref().map(String::as_str),
+            SyncedBookmarkValue::Unspecified => None,
+        }
+    }
+
+    // Trims whitespace and collapses internal runs of whitespace to a
+    // single space, so that cosmetic differences don't defeat matching.
+    fn normalize_title(title: Option<&str>) -> String {
+        title
+            .unwrap_or("")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn unwrap_or_default<T: Clone>(val: &SyncedBookmarkValue<T>, default: T) -> T {
+        match val {
+            SyncedBookmarkValue::Specified(val) => val.clone(),
+            SyncedBookmarkValue::Unspecified => default,
+        }
+    }
+
+    // Find the placeId for `url`, creating a new moz_places row for it if
+    // one doesn't already exist.
+    fn ensure_place_id(conn: &PlacesDb, url: &Url) -> Result<RowId> {
+        let href = url.as_str();
+        if let Some(id) = conn.try_query_row(
+            "SELECT id FROM moz_places WHERE url_hash = hash(:href) AND url = :href",
+            &[(":href", &href)],
+            |row| row.get_checked::<_, RowId>(0),
+            true,
+        )? {
+            return Ok(id);
+        }
+        conn.execute_named_cache(
+            "INSERT INTO moz_places(guid, url, url_hash, frecency)
+             VALUES (generate_guid(), :href, hash(:href), -1)",
+            &[(":href", &href)],
+        )?;
+        Ok(RowId(conn.conn().last_insert_rowid()))
+    }
+
+    // Stage `tags` for `item_id`, interning each tag into moz_tags first.
+    fn stage_tags(conn: &PlacesDb, item_id: RowId, tags: &[String]) -> Result<()> {
+        for tag in tags {
+            conn.execute_named_cache(
+                "INSERT OR IGNORE INTO moz_tags(tag, lastModified) VALUES (:tag, 0)",
+                &[(":tag", tag)],
+            )?;
+            conn.execute_named_cache(
+                "INSERT INTO moz_bookmarks_synced_tag_relation(itemId, tagId)
+                 SELECT :item_id, id FROM moz_tags WHERE tag = :tag",
+                &[(":item_id", &item_id), (":tag", tag)],
+            )?;
+        }
+        Ok(())
+    }
+
     // Get a record from the DB.
     pub fn get(conn: &PlacesDb, guid: &SyncGuid) -> Result<Option<Self>> {
         Ok(conn.try_query_row(
@@ -168,6 +552,38 @@ impl SyncedBookmarkItem {
         )?)
     }
 
+    // Get every item with the given parent, in GUID order.
+    pub fn get_by_parent(conn: &PlacesDb, parent_guid: &SyncGuid) -> Result<Vec<Self>> {
+        conn.query_rows_and_then_named(
+            "SELECT b.*, p.url, group_concat(t.tag) AS tags
+                               FROM moz_bookmarks_synced b
+                               LEFT JOIN moz_places p on b.placeId = p.id
+                               LEFT JOIN moz_bookmarks_synced_tag_relation r ON r.itemId = b.id
+                               LEFT JOIN moz_tags t ON t.id = r.tagId
+                               WHERE b.parentGuid = :parent
+                               GROUP BY b.id
+                               ORDER BY b.guid",
+            &[(":parent", parent_guid)],
+            Self::from_row,
+        )
+    }
+
+    // Get every item whose GUID starts with `prefix`, in GUID order.
+    pub fn list_by_prefix(conn: &PlacesDb, prefix: &str) -> Result<Vec<Self>> {
+        conn.query_rows_and_then_named(
+            "SELECT b.*, p.url, group_concat(t.tag) AS tags
+                               FROM moz_bookmarks_synced b
+                               LEFT JOIN moz_places p on b.placeId = p.id
+                               LEFT JOIN moz_bookmarks_synced_tag_relation r ON r.itemId = b.id
+                               LEFT JOIN moz_tags t ON t.id = r.tagId
+                               WHERE b.guid GLOB :prefix || '*'
+                               GROUP BY b.id
+                               ORDER BY b.guid",
+            &[(":prefix", &prefix)],
+            Self::from_row,
+        )
+    }
+
     // Return a new SyncedBookmarkItem from a database row. All values will
     // be SyncedBookmarkValue::Specified.
     fn from_row(row: &Row) -> Result<Self> {
@@ -215,4 +631,41 @@ impl SyncedBookmarkItem {
             tags: SyncedBookmarkValue::Specified(tags),
         })
     }
-}
\ No newline at end of file
+}
+
+/// A lightweight optimistic-concurrency guard, borrowing the "read the
+/// total sync change count, then re-check it inside the write transaction"
+/// trick the store uses before it trusts its computed tree. Tooling that
+/// stages a batch of `SyncedBookmarkItem`s can snapshot before staging and
+/// verify before committing, aborting and retrying if local bookmarks
+/// mutated concurrently - without holding a transaction open the whole
+/// time.
+pub struct MirrorSnapshot {
+    sync_change_count: i64,
+}
+
+impl MirrorSnapshot {
+    pub fn new(conn: &PlacesDb) -> Result<Self> {
+        Ok(Self {
+            sync_change_count: Self::total_sync_change_count(conn)?,
+        })
+    }
+
+    // Returns `true` if the total sync change count is the same as when
+    // this snapshot was taken - that is, nothing local has changed.
+    pub fn verify_unchanged(&self, conn: &PlacesDb) -> Result<bool> {
+        Ok(Self::total_sync_change_count(conn)? == self.sync_change_count)
+    }
+
+    fn total_sync_change_count(conn: &PlacesDb) -> Result<i64> {
+        Ok(conn
+            .try_query_row(
+                "SELECT SUM(syncChangeCounter) FROM moz_bookmarks",
+                &[],
+                |row| row.get_checked::<_, Option<i64>>(0),
+                false,
+            )?
+            .unwrap_or_default()
+            .unwrap_or_default())
+    }
+}